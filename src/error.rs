@@ -0,0 +1,234 @@
+use std::io;
+use std::string::FromUtf8Error;
+use std::thread::sleep;
+use std::time::Duration;
+
+use rand::random;
+use thiserror::Error as ThisError;
+
+use crate::{Config, Fallible};
+
+/// Errors raised while talking to the cloud provider or a machine over SSH.
+///
+/// `Transient` covers failures worth retrying (dropped connections, rate
+/// limits); `Provider` covers failures the provider reported deliberately
+/// (bad image, unknown droplet); `Fatal` covers everything else. Both
+/// `Provider` and `Fatal` are unrecoverable by the time they escape `retry`,
+/// so `abort_on_fatal` treats them the same: it aborts the whole job and
+/// triggers best-effort cleanup of already-created machines.
+#[derive(Debug, ThisError)]
+pub enum Error {
+    #[error("{0}")]
+    Transient(String),
+    #[error("{0}")]
+    Provider(String),
+    #[error("{0}")]
+    Fatal(String),
+}
+
+impl Error {
+    fn is_transient(&self) -> bool {
+        matches!(self, Error::Transient(_))
+    }
+}
+
+/// Most `io::Error`s raised in this crate come from reading/writing local
+/// files (the job state, the binary/inputs, task results), which are never
+/// worth retrying, so they're classified `Fatal` by default. The handful of
+/// call sites where an `io::Error` actually comes from the network (an SSH
+/// connection or channel) convert it to `Error::Transient` explicitly
+/// instead of relying on this impl.
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Fatal(err.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(err: FromUtf8Error) -> Self {
+        Error::Fatal(err.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Self {
+        Error::Fatal(err.to_string())
+    }
+}
+
+/// libssh2's `LIBSSH2_ERROR_AUTHENTICATION_FAILED`, not exposed as a
+/// constant by the `ssh2` crate.
+const LIBSSH2_ERROR_AUTHENTICATION_FAILED: i32 = -18;
+
+impl From<ssh2::Error> for Error {
+    fn from(err: ssh2::Error) -> Self {
+        // A rejected key/identity is a config problem, not a blip worth
+        // burning `max_retries` attempts on, so it's classified `Fatal`
+        // instead of the `Transient` default for everything else.
+        match err.code() {
+            ssh2::ErrorCode::Session(LIBSSH2_ERROR_AUTHENTICATION_FAILED) => {
+                Error::Fatal(err.to_string())
+            }
+            _ => Error::Transient(err.to_string()),
+        }
+    }
+}
+
+impl From<&str> for Error {
+    fn from(err: &str) -> Self {
+        Error::Fatal(err.to_owned())
+    }
+}
+
+impl From<String> for Error {
+    fn from(err: String) -> Self {
+        Error::Fatal(err)
+    }
+}
+
+/// Runs `op`, retrying transient failures up to `config.max_retries` times
+/// with a `base_delay * 2^attempt` backoff plus random jitter in
+/// `[0, base_delay)` so that many machines hitting the same outage don't
+/// all hammer the provider/host back-to-back. Provider and other
+/// non-transient errors are returned immediately without retrying, keeping
+/// whatever classification their own constructor gave them. Only once a
+/// transient failure's retries are exhausted does it get reclassified as
+/// `Fatal`, since at that point it's no better than any other unrecoverable
+/// failure; `abort_on_fatal` treats both `Fatal` and `Provider` the same.
+pub fn retry<T>(config: &Config, mut op: impl FnMut() -> Fallible<T>) -> Fallible<T> {
+    let mut attempt = 0;
+
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(err) if err.is_transient() && attempt < config.max_retries => {
+                let base_delay = Duration::from_secs(config.base_delay);
+                // `2u32.pow(attempt)` would panic once `attempt` reaches 32,
+                // a reachable value if `max_retries` is configured that high;
+                // saturate to the largest representable backoff instead.
+                let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+                let backoff = base_delay.checked_mul(factor).unwrap_or(Duration::MAX);
+                let jitter = Duration::from_secs_f64(random::<f64>() * base_delay.as_secs_f64());
+
+                sleep(backoff.saturating_add(jitter));
+
+                attempt += 1;
+            }
+            Err(err) if err.is_transient() => return Err(Error::Fatal(err.to_string())),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_retries: u32) -> Config {
+        Config {
+            max_machines: 1,
+            tasks_per_machine: 1,
+            name: String::new(),
+            image: String::new(),
+            size: String::new(),
+            region: String::new(),
+            provider: Default::default(),
+            static_hosts: Vec::new(),
+            ssh_key: String::new(),
+            ssh_user: String::new(),
+            ssh_identity_file: Default::default(),
+            install_cmd: String::new(),
+            check_interval: 0,
+            fetch_partial_results: false,
+            shell_fallback: false,
+            max_retries,
+            base_delay: 0,
+            max_parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn retry_succeeds_without_retrying_on_ok() {
+        let mut calls = 0;
+
+        let result = retry(&config(3), || {
+            calls += 1;
+            Ok::<_, Error>(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn retry_exhausts_transient_failures_into_fatal() {
+        let mut calls = 0;
+
+        let result = retry(&config(2), || {
+            calls += 1;
+            Err::<(), _>(Error::Transient("boom".into()))
+        });
+
+        // One initial attempt plus `max_retries` retries.
+        assert_eq!(calls, 3);
+        assert!(matches!(result, Err(Error::Fatal(_))));
+    }
+
+    #[test]
+    fn retry_recovers_after_transient_failures() {
+        let mut calls = 0;
+
+        let result = retry(&config(3), || {
+            calls += 1;
+            if calls < 3 {
+                Err(Error::Transient("boom".into()))
+            } else {
+                Ok(())
+            }
+        });
+
+        assert_eq!(calls, 3);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn retry_does_not_retry_provider_errors() {
+        let mut calls = 0;
+
+        let result = retry(&config(3), || {
+            calls += 1;
+            Err::<(), _>(Error::Provider("bad image".into()))
+        });
+
+        assert_eq!(calls, 1);
+        assert!(matches!(result, Err(Error::Provider(_))));
+    }
+
+    #[test]
+    fn retry_does_not_panic_when_attempt_exceeds_u32_pow_shift() {
+        let mut calls = 0;
+
+        // `max_retries` above 32 pushes `attempt` past what `2u32.pow`
+        // can represent; this must saturate the backoff instead of panicking.
+        let result = retry(&config(33), || {
+            calls += 1;
+            Err::<(), _>(Error::Transient("boom".into()))
+        });
+
+        assert_eq!(calls, 34);
+        assert!(matches!(result, Err(Error::Fatal(_))));
+    }
+
+    #[test]
+    fn retry_does_not_retry_fatal_errors() {
+        let mut calls = 0;
+
+        let result = retry(&config(3), || {
+            calls += 1;
+            Err::<(), _>(Error::Fatal("disk full".into()))
+        });
+
+        assert_eq!(calls, 1);
+        assert!(matches!(result, Err(Error::Fatal(_))));
+    }
+}
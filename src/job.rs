@@ -1,8 +1,9 @@
 use std::collections::VecDeque;
 use std::fs::{read, write};
 use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime};
+use std::sync::{Mutex, OnceLock};
 
+use blake3::Hasher;
 use serde::{Deserialize, Serialize};
 use serde_yaml::{from_slice, to_vec};
 
@@ -13,8 +14,17 @@ pub struct Job {
     pub binary: PathBuf,
     pub inputs: Vec<PathBuf>,
     pub config: Config,
-    pub machines: Vec<Machine>,
-    pub tasks: VecDeque<Task>,
+    pub machines: Vec<Mutex<Machine>>,
+    pub tasks: Mutex<VecDeque<Task>>,
+    /// Serializes calls to `write` so that concurrent machine workers never
+    /// interleave two YAML snapshots into the same file.
+    #[serde(skip)]
+    write_lock: Mutex<()>,
+    /// Memoizes `content_hash`, which is otherwise invariant for the whole
+    /// job, so dispatching many tasks does not re-read and re-hash
+    /// `binary`/`inputs` from disk once per task.
+    #[serde(skip)]
+    content_hash: OnceLock<String>,
 }
 
 impl Job {
@@ -25,6 +35,8 @@ impl Job {
     }
 
     pub fn write<P: AsRef<Path>>(&self, path: P) -> Fallible {
+        let _guard = self.write_lock.lock().unwrap();
+
         write(path, &to_vec(self)?)?;
 
         Ok(())
@@ -33,6 +45,8 @@ impl Job {
     pub fn max_machines(&self) -> usize {
         let max_tasks = self
             .tasks
+            .lock()
+            .unwrap()
             .iter()
             .map(|task| {
                 task.range
@@ -44,25 +58,19 @@ impl Job {
         max_tasks.min(self.config.max_machines)
     }
 
-    pub fn next_check(&self) -> Option<Duration> {
-        let next_check = self
-            .machines
-            .iter()
-            .map(|machine| machine.next_check)
-            .min()
-            .unwrap();
-
-        next_check.duration_since(SystemTime::now()).ok()
-    }
-
-    pub fn next_task(tasks: &mut VecDeque<Task>) -> Option<Task> {
-        let mut task = tasks.pop_front()?;
+    /// Safe to call concurrently from multiple machine workers, each
+    /// competing for the next piece of work.
+    pub fn next_task(&self) -> Fallible<Option<Task>> {
+        let mut task = match self.tasks.lock().unwrap().pop_front() {
+            Some(task) => task,
+            None => return Ok(None),
+        };
 
         if let Some(range) = &task.range {
             if range.end - range.start > 1 {
                 let mut task = task.clone();
                 task.range.as_mut().unwrap().start += 1;
-                tasks.push_back(task);
+                self.tasks.lock().unwrap().push_back(task);
             }
 
             let index = range.start.to_string();
@@ -70,6 +78,156 @@ impl Job {
             task.cmd = task.cmd.replace("{{index}}", &index);
         }
 
-        Some(task)
+        task.hash = Some(self.task_hash(&task)?);
+
+        Ok(Some(task))
+    }
+
+    /// Content hash over `binary`, every file in `inputs` and the task's
+    /// expanded `cmd`/`name`, used as the cache key for `Task::is_cached`.
+    fn task_hash(&self, task: &Task) -> Fallible<String> {
+        let mut hasher = Hasher::new();
+        hasher.update(self.content_hash()?.as_bytes());
+        hasher.update(task.cmd.as_bytes());
+        hasher.update(task.name.as_bytes());
+
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+
+    /// Hashes `binary` and every file in `inputs`, memoizing the result since
+    /// it is the same for every task in the job.
+    fn content_hash(&self) -> Fallible<&str> {
+        if let Some(hash) = self.content_hash.get() {
+            return Ok(hash);
+        }
+
+        let mut hasher = Hasher::new();
+        hasher.update(&read(&self.binary)?);
+
+        for input in &self.inputs {
+            hasher.update(&read(input)?);
+        }
+
+        Ok(self
+            .content_hash
+            .get_or_init(|| hasher.finalize().to_hex().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_file;
+
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("dospawn-test-{}-{}", std::process::id(), name));
+        write(&path, contents).unwrap();
+        path
+    }
+
+    fn job(binary: PathBuf, inputs: Vec<PathBuf>) -> Job {
+        Job {
+            binary,
+            inputs,
+            config: Config {
+                max_machines: 1,
+                tasks_per_machine: 1,
+                name: String::new(),
+                image: String::new(),
+                size: String::new(),
+                region: String::new(),
+                provider: Default::default(),
+                static_hosts: Vec::new(),
+                ssh_key: String::new(),
+                ssh_user: String::new(),
+                ssh_identity_file: Default::default(),
+                install_cmd: String::new(),
+                check_interval: 0,
+                fetch_partial_results: false,
+                shell_fallback: false,
+                max_retries: 0,
+                base_delay: 0,
+                max_parallelism: 1,
+            },
+            machines: Vec::new(),
+            tasks: Mutex::new(VecDeque::new()),
+            write_lock: Mutex::new(()),
+            content_hash: OnceLock::new(),
+        }
+    }
+
+    fn task(name: &str, cmd: &str) -> Task {
+        Task {
+            name: name.to_owned(),
+            cmd: cmd.to_owned(),
+            range: None,
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn task_hash_is_stable_for_same_inputs() {
+        let binary = temp_file("task-hash-stable-binary", b"binary bytes");
+        let job = job(binary.clone(), Vec::new());
+
+        let first = job.task_hash(&task("task", "cmd")).unwrap();
+        let second = job.task_hash(&task("task", "cmd")).unwrap();
+
+        assert_eq!(first, second);
+
+        remove_file(&binary).unwrap();
+    }
+
+    #[test]
+    fn task_hash_differs_for_different_cmd_or_name() {
+        let binary = temp_file("task-hash-differs-binary", b"binary bytes");
+        let job = job(binary.clone(), Vec::new());
+
+        let base = job.task_hash(&task("task", "cmd")).unwrap();
+        let different_cmd = job.task_hash(&task("task", "other cmd")).unwrap();
+        let different_name = job.task_hash(&task("other task", "cmd")).unwrap();
+
+        assert_ne!(base, different_cmd);
+        assert_ne!(base, different_name);
+
+        remove_file(&binary).unwrap();
+    }
+
+    #[test]
+    fn task_hash_differs_when_binary_or_inputs_change() {
+        let binary_a = temp_file("task-hash-binary-a", b"binary a");
+        let binary_b = temp_file("task-hash-binary-b", b"binary b");
+        let input = temp_file("task-hash-input", b"input bytes");
+
+        let job_a = job(binary_a.clone(), vec![input.clone()]);
+        let job_b = job(binary_b.clone(), vec![input.clone()]);
+        let job_a_no_input = job(binary_a.clone(), Vec::new());
+
+        let hash_a = job_a.task_hash(&task("task", "cmd")).unwrap();
+        let hash_b = job_b.task_hash(&task("task", "cmd")).unwrap();
+        let hash_a_no_input = job_a_no_input.task_hash(&task("task", "cmd")).unwrap();
+
+        assert_ne!(hash_a, hash_b);
+        assert_ne!(hash_a, hash_a_no_input);
+
+        remove_file(&binary_a).unwrap();
+        remove_file(&binary_b).unwrap();
+        remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn content_hash_is_memoized() {
+        let binary = temp_file("content-hash-memoized", b"binary bytes");
+        let job = job(binary.clone(), Vec::new());
+
+        let first = job.content_hash().unwrap().to_owned();
+
+        // Even if the file on disk changes, the memoized hash must not.
+        write(&binary, b"different bytes").unwrap();
+        let second = job.content_hash().unwrap().to_owned();
+
+        assert_eq!(first, second);
+
+        remove_file(&binary).unwrap();
     }
 }
@@ -0,0 +1,92 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+
+/// A GNU-make-style jobserver: a fixed pool of tokens shared between worker
+/// threads so the total number of concurrent SSH/provider operations never
+/// exceeds `Config::max_parallelism`, no matter how many machines are being
+/// serviced at once.
+pub struct Tokens {
+    acquire: Mutex<Receiver<()>>,
+    release: SyncSender<()>,
+}
+
+impl Tokens {
+    pub fn new(count: usize) -> Self {
+        let (release, receiver) = sync_channel(count);
+
+        for _ in 0..count {
+            release
+                .send(())
+                .expect("receiver cannot be disconnected yet");
+        }
+
+        Self {
+            acquire: Mutex::new(receiver),
+            release,
+        }
+    }
+
+    /// Blocks until a token is available; releases it back to the pool when
+    /// the returned `Token` is dropped.
+    pub fn acquire(&self) -> Token<'_> {
+        self.acquire
+            .lock()
+            .unwrap()
+            .recv()
+            .expect("sender cannot be disconnected while `self` is alive");
+
+        Token { tokens: self }
+    }
+}
+
+pub struct Token<'a> {
+    tokens: &'a Tokens,
+}
+
+impl Drop for Token<'_> {
+    fn drop(&mut self) {
+        let _ = self.tokens.release.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn acquire_does_not_block_while_tokens_are_free() {
+        let tokens = Tokens::new(2);
+
+        let _first = tokens.acquire();
+        let _second = tokens.acquire();
+    }
+
+    #[test]
+    fn acquire_blocks_until_a_token_is_released() {
+        let tokens = Tokens::new(2);
+        let first = tokens.acquire();
+        let second = tokens.acquire();
+
+        let (done_tx, done_rx) = channel();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _third = tokens.acquire();
+                done_tx.send(()).unwrap();
+            });
+
+            // Both tokens are held, so the third acquire must not complete yet.
+            assert!(done_rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+            drop(first);
+
+            // Releasing one token must let the pending acquire through.
+            assert!(done_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        });
+
+        drop(second);
+    }
+}
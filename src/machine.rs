@@ -1,129 +1,226 @@
-use std::process::{Command, Stdio};
-use std::time::SystemTime;
+use std::fs::read;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
 
 use serde::{Deserialize, Serialize};
+use ssh2::{ExtendedData, Session};
 
-use crate::{job::Job, task::Task, Config, Fallible, SSH_OPTS};
+use crate::{
+    error::{retry, Error},
+    provider::provider,
+    task::Task,
+    Config, Fallible, SSH_OPTS,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct Machine {
     pub name: String,
     pub id: String,
     pub ip: String,
-    pub task: Option<Task>,
-    pub next_check: SystemTime,
+    pub tasks: Vec<Option<Task>>,
+    /// Set once this machine has been deleted so a resumed job does not try
+    /// to provision or schedule work on it again.
+    #[serde(default)]
+    pub retired: bool,
+    #[serde(skip)]
+    session: Option<Session>,
 }
 
 impl Machine {
     pub fn create(name: String, config: &Config) -> Fallible<Self> {
-        println!("Creating machine {}", name);
-
-        let doctl = Command::new("doctl")
-            .args(&[
-                "compute",
-                "droplet",
-                "create",
-                "--wait",
-                "--image",
-                &config.image,
-                "--size",
-                &config.size,
-                "--region",
-                &config.region,
-                "--ssh-keys",
-                &config.ssh_key,
-                "--format",
-                "ID,PublicIPv4",
-                "--no-header",
-            ])
-            .arg(&name)
-            .stderr(Stdio::inherit())
-            .output()?;
-
-        if !doctl.status.success() {
-            return Err(format!("Failed to create machine {}", name).into());
-        }
+        let provider = provider(config);
 
-        let stdout = String::from_utf8(doctl.stdout)?;
-        let mut fields = stdout.split_whitespace();
+        let (id, ip) = retry(config, || {
+            println!("Creating machine {}", name);
 
-        let id = fields.next().ok_or("Missing Droplet ID")?.to_owned();
-        let ip = fields.next().ok_or("Missing Droplet IP")?.to_owned();
+            provider.create(&name, config)
+        })?;
 
         Ok(Self {
             name,
             id,
             ip,
-            task: None,
-            next_check: SystemTime::now() + config.check_interval,
+            tasks: vec![None; config.tasks_per_machine],
+            retired: false,
+            session: None,
         })
     }
 
-    pub fn copy_binary_and_inputs(&self, job: &Job) -> Fallible {
+    pub fn copy_binary_and_inputs(
+        &mut self,
+        config: &Config,
+        binary: &Path,
+        inputs: &[PathBuf],
+    ) -> Fallible {
         println!("Copying binary and inputs to machine {}", self.name);
 
-        let scp = Command::new("scp")
-            .args(SSH_OPTS)
-            .arg("-C")
-            .arg(&job.binary)
-            .args(&job.inputs)
-            .arg(format!("{}@{}:", job.config.ssh_user, self.ip))
-            .status()?;
-
-        if !scp.success() {
-            return Err(
-                format!("Failed to copy binary and inputs to machine {}", self.name,).into(),
-            );
+        if config.shell_fallback {
+            retry(config, || {
+                let scp = Command::new("scp")
+                    .args(SSH_OPTS)
+                    .arg("-C")
+                    .arg(binary)
+                    .args(inputs)
+                    .arg(format!("{}@{}:", config.ssh_user, self.ip))
+                    .status()?;
+
+                if !scp.success() {
+                    return Err(classify_ssh_failure(
+                        format!("Failed to copy binary and inputs to machine {}", self.name),
+                        scp,
+                    ));
+                }
+
+                Ok(())
+            })
+        } else {
+            retry(config, || {
+                for path in std::iter::once(binary).chain(inputs.iter().map(PathBuf::as_path)) {
+                    self.scp_upload(config, path)?;
+                }
+
+                Ok(())
+            })
         }
+    }
+
+    fn scp_upload(&mut self, config: &Config, path: &Path) -> Fallible {
+        let data = read(path)?;
+        let file_name = path.file_name().ok_or("Missing file name")?;
 
-        Ok(())
+        self.with_session(config, |session| {
+            let mut remote =
+                session.scp_send(file_name.as_ref(), 0o644, data.len() as u64, None)?;
+            remote
+                .write_all(&data)
+                .map_err(|err| Error::Transient(err.to_string()))?;
+
+            remote.send_eof()?;
+            remote.wait_eof()?;
+            remote.close()?;
+            remote.wait_close()?;
+
+            Ok(())
+        })
     }
 
-    pub fn install_required_software(&self, config: &Config) -> Fallible {
+    pub fn install_required_software(&mut self, config: &Config) -> Fallible {
         println!("Installing required software on machine {}", self.name);
 
-        let ssh = Command::new("ssh")
-            .args(SSH_OPTS)
-            .arg(format!("{}@{}", config.ssh_user, self.ip))
-            .arg("--")
-            .arg(&config.install_cmd)
-            .status()?;
-
-        if !ssh.success() {
-            return Err(format!(
-                "Failed to install required bundles on machine {}",
-                self.name,
-            )
-            .into());
+        if config.shell_fallback {
+            retry(config, || {
+                let ssh = Command::new("ssh")
+                    .args(SSH_OPTS)
+                    .arg(format!("{}@{}", config.ssh_user, self.ip))
+                    .arg("--")
+                    .arg(&config.install_cmd)
+                    .status()?;
+
+                if !ssh.success() {
+                    return Err(classify_ssh_failure(
+                        format!(
+                            "Failed to install required bundles on machine {}",
+                            self.name
+                        ),
+                        ssh,
+                    ));
+                }
+
+                Ok(())
+            })
+        } else {
+            let name = self.name.clone();
+
+            retry(config, || {
+                self.with_session(config, |session| {
+                    let mut channel = session.channel_session()?;
+
+                    // Merge stderr into the stdout stream before exec so a single
+                    // read_to_end drains both; reading them one at a time would
+                    // deadlock if install_cmd fills the other stream's flow-control
+                    // window while we're still blocked draining the first.
+                    channel.handle_extended_data(ExtendedData::Merge)?;
+                    channel.exec(&config.install_cmd)?;
+
+                    let mut output = Vec::new();
+                    channel
+                        .stream(0)
+                        .read_to_end(&mut output)
+                        .map_err(|err| Error::Transient(err.to_string()))?;
+
+                    channel.wait_close()?;
+
+                    if channel.exit_status()? != 0 {
+                        return Err(Error::Provider(format!(
+                            "Failed to install required bundles on machine {}",
+                            name,
+                        )));
+                    }
+
+                    Ok(())
+                })
+            })
         }
-
-        Ok(())
     }
 
-    pub fn delete(&self) -> Fallible {
-        println!("Deleting machine {}", self.name);
+    pub fn delete(&self, config: &Config) -> Fallible {
+        let provider = provider(config);
 
-        let doctl = Command::new("doctl")
-            .args(&["compute", "droplet", "delete", "--force"])
-            .arg(&self.id)
-            .status()?;
+        retry(config, || {
+            println!("Deleting machine {}", self.name);
 
-        if !doctl.success() {
-            return Err(format!("Failed to delete machine {}", self.name).into());
+            provider.delete(&self.id, config)
+        })
+    }
+
+    /// Returns the cached authenticated SSH session for this machine, establishing
+    /// and handshaking a new one on first use so `start`/`check`/file transfer can
+    /// share a single connection instead of paying for a fresh handshake each time.
+    pub(crate) fn session(&mut self, config: &Config) -> Fallible<&Session> {
+        if self.session.is_none() {
+            let tcp = TcpStream::connect((self.ip.as_str(), 22))
+                .map_err(|err| Error::Transient(err.to_string()))?;
+
+            let mut session = Session::new()?;
+            session.set_tcp_stream(tcp);
+            session.handshake()?;
+            session.userauth_pubkey_file(
+                &config.ssh_user,
+                None,
+                &config.ssh_identity_file,
+                None,
+            )?;
+
+            self.session = Some(session);
         }
 
-        Ok(())
+        Ok(self.session.as_ref().unwrap())
     }
 
-    pub fn next_check(next_check: &mut SystemTime, config: &Config) -> bool {
-        let now = SystemTime::now();
+    /// Runs `f` against the cached session, dropping it on failure so the next
+    /// attempt reconnects instead of reusing a session left in a broken state.
+    pub(crate) fn with_session<T>(
+        &mut self,
+        config: &Config,
+        f: impl FnOnce(&Session) -> Fallible<T>,
+    ) -> Fallible<T> {
+        let session = self.session(config)?;
+        let result = f(session);
+
+        if result.is_err() {
+            self.session = None;
+        }
 
-        if *next_check <= now {
-            *next_check = now + config.check_interval;
+        result
+    }
+}
 
-            true
-        } else {
-            false
-        }
+pub(crate) fn classify_ssh_failure(message: String, status: ExitStatus) -> Error {
+    if status.code() == Some(255) {
+        Error::Transient(message)
+    } else {
+        Error::Provider(message)
     }
 }
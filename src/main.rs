@@ -1,109 +1,300 @@
 use std::collections::VecDeque;
 use std::env::args_os;
-use std::error::Error;
-use std::thread::sleep;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep, spawn};
 use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
 
+mod error;
 mod job;
+mod jobserver;
 mod machine;
+mod provider;
 mod task;
 
-use crate::{job::Job, machine::Machine};
+use crate::{error::Error, job::Job, jobserver::Tokens, machine::Machine, provider::ProviderKind};
 
 fn main() -> Fallible {
-    let path = args_os().nth(1).ok_or("Missing path argument")?;
+    let path: PathBuf = args_os().nth(1).ok_or("Missing path argument")?.into();
 
     let mut job = Job::read(&path)?;
 
-    while job.machines.len() < job.max_machines() {
-        let machine = Machine::create(
-            format!("{}-{}", job.config.name, job.machines.len()),
-            &job.config,
-        )?;
-        job.machines.push(machine);
+    if job.config.max_parallelism == 0 {
+        // `Tokens::new(0)` would seed zero tokens, so the very first
+        // `acquire()` would block in `recv()` forever instead of erroring.
+        return Err("max_parallelism must be at least 1".into());
+    }
+
+    let tokens = Tokens::new(job.config.max_parallelism);
 
-        job.write(&path)?;
+    if let Err(err) = provision(&mut job, &path, &tokens) {
+        abort_on_fatal(&job, &path, &err);
+        return Err(err);
     }
 
-    let mut todo = VecDeque::new();
+    let job = Arc::new(job);
+    let tokens = Arc::new(tokens);
 
-    for (machine_idx, machine) in job.machines.iter().enumerate() {
-        if machine.tasks.iter().all(|task| task.is_none()) {
-            machine.copy_binary_and_inputs(&job)?;
-            machine.install_required_software(&job.config)?;
+    match run(&job, &path, &tokens) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            abort_on_fatal(&job, &path, &err);
+            Err(err)
+        }
+    }
+}
+
+fn abort_on_fatal(job: &Job, path: &Path, err: &Error) {
+    if matches!(err, Error::Fatal(_) | Error::Provider(_)) {
+        println!("Aborting job after fatal error, deleting all machines");
+
+        for machine in &job.machines {
+            let mut machine = machine.lock().unwrap();
+
+            if !machine.retired {
+                let _ = machine.delete(&job.config);
+                machine.retired = true;
+            }
+        }
+
+        let _ = job.write(path);
+    }
+}
+
+/// Creates the machines still missing towards `Job::max_machines` in
+/// parallel before any worker thread starts, so every worker can assume its
+/// machine already exists for the rest of the run.
+fn provision(job: &mut Job, path: &Path, tokens: &Tokens) -> Fallible {
+    let start = job.machines.len();
+    let target = job.max_machines();
+    let config = &job.config;
+
+    let machines: Vec<Fallible<Machine>> = thread::scope(|scope| {
+        let handles: Vec<_> = (start..target)
+            .map(|machine_idx| {
+                scope.spawn(move || {
+                    let _token = tokens.acquire();
+
+                    Machine::create(format!("{}-{}", config.name, machine_idx), config)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("provisioning thread panicked"))
+            .collect()
+    });
+
+    // Track every machine that was actually created before bailing on the
+    // first error, so a mid-batch failure still leaves its already-created
+    // siblings in `job.machines` (and thus written to disk and reachable by
+    // `abort_on_fatal`) instead of silently dropping and leaking them.
+    let mut first_err = None;
+
+    for machine in machines {
+        match machine {
+            Ok(machine) => {
+                job.machines.push(Mutex::new(machine));
+
+                job.write(path)?;
+            }
+            Err(err) => {
+                first_err.get_or_insert(err);
+            }
         }
+    }
 
-        for task_idx in 0..job.config.tasks_per_machine {
-            todo.push_back((Instant::now(), machine_idx, task_idx));
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+fn run(job: &Arc<Job>, path: &Path, tokens: &Arc<Tokens>) -> Fallible {
+    // Shared between every machine worker so a fatal/provider error on one
+    // machine promptly stops the others instead of letting them keep
+    // provisioning/polling until they naturally drain their own `todo`
+    // queue, which for a long-running job could be the rest of it.
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let handles: Vec<_> = (0..job.machines.len())
+        .map(|machine_idx| {
+            let job = Arc::clone(job);
+            let path = path.to_owned();
+            let tokens = Arc::clone(&tokens);
+            let stop = Arc::clone(&stop);
+
+            spawn(move || machine_worker(&job, &path, machine_idx, &tokens, &stop))
+        })
+        .collect();
+
+    let mut result = Ok(());
+
+    for handle in handles {
+        if let Err(err) = handle.join().expect("worker thread panicked") {
+            if result.is_ok() {
+                result = Err(err);
+            }
         }
     }
 
-    while let Some((deadline, machine_idx, task_idx)) = todo.pop_front() {
+    result
+}
+
+/// Services a single machine for the lifetime of the job: starts, checks and
+/// fetches task results across its `tasks_per_machine` slots until none
+/// remain, then deletes the machine. Sets `stop` on its first error so
+/// siblings servicing other machines stop picking up new work, and bails
+/// out early itself once another worker has set `stop`.
+fn machine_worker(
+    job: &Job,
+    path: &Path,
+    machine_idx: usize,
+    tokens: &Tokens,
+    stop: &AtomicBool,
+) -> Fallible {
+    let result = machine_worker_inner(job, path, machine_idx, tokens, stop);
+
+    if result.is_err() {
+        stop.store(true, Ordering::Relaxed);
+    }
+
+    result
+}
+
+fn machine_worker_inner(
+    job: &Job,
+    path: &Path,
+    machine_idx: usize,
+    tokens: &Tokens,
+    stop: &AtomicBool,
+) -> Fallible {
+    if stop.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    {
+        let mut machine = job.machines[machine_idx].lock().unwrap();
+
+        if machine.retired {
+            return Ok(());
+        }
+
+        if machine.tasks.iter().all(Option::is_none) {
+            let _token = tokens.acquire();
+
+            machine.copy_binary_and_inputs(&job.config, &job.binary, &job.inputs)?;
+            machine.install_required_software(&job.config)?;
+        }
+    }
+
+    job.write(path)?;
+
+    let mut todo = VecDeque::new();
+
+    for task_idx in 0..job.config.tasks_per_machine {
+        todo.push_back((Instant::now(), task_idx));
+    }
+
+    while let Some((deadline, task_idx)) = todo.pop_front() {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
         if let Some(duration) = deadline.checked_duration_since(Instant::now()) {
             sleep(duration);
         }
 
-        let machine = &mut job.machines[machine_idx];
+        let mut machine = job.machines[machine_idx].lock().unwrap();
+
+        if machine.tasks[task_idx].is_some() {
+            let _token = tokens.acquire();
+
+            // Take the task out so `machine` can be borrowed mutably by
+            // `check`/`fetch_results` at the same time.
+            let task = machine.tasks[task_idx].take().unwrap();
 
-        if let Some(task) = &machine.tasks[task_idx] {
-            let finished = task.check(&job.config, machine)?;
+            let finished = task.check(&job.config, &mut machine)?;
 
             if finished || job.config.fetch_partial_results {
-                task.fetch_results(&job.config, machine)?;
+                task.fetch_results(&job.config, &mut machine, finished)?;
             }
 
-            if finished {
-                machine.tasks[task_idx] = None;
+            machine.tasks[task_idx] = if finished { None } else { Some(task) };
 
-                job.write(&path)?;
-            }
+            drop(machine);
+
+            job.write(path)?;
+        } else {
+            drop(machine);
         }
 
-        let machine = &mut job.machines[machine_idx];
+        let started = if job.machines[machine_idx].lock().unwrap().tasks[task_idx].is_some() {
+            true
+        } else {
+            let mut next = None;
 
-        if machine.tasks[task_idx].is_none() {
-            if let Some(task) = Job::next_task(&mut job.tasks) {
-                task.start(&job.config, machine)?;
+            while let Some(task) = job.next_task()? {
+                if task.is_cached() {
+                    println!(
+                        "Reusing cached results for task {} on machine {}",
+                        task.name,
+                        job.machines[machine_idx].lock().unwrap().name
+                    );
 
-                machine.tasks[task_idx] = Some(task);
+                    job.write(path)?;
 
-                job.write(&path)?;
-            } else {
-                if machine.tasks.iter().all(|task| task.is_none()) {
-                    machine.delete()?;
+                    continue;
+                }
+
+                next = Some(task);
 
-                    let mut todo_idx = 0;
+                break;
+            }
 
-                    while todo_idx < todo.len() {
-                        let (_, machine_idx1, _) = &mut todo[todo_idx];
+            match next {
+                Some(task) => {
+                    let mut machine = job.machines[machine_idx].lock().unwrap();
 
-                        if *machine_idx1 == machine_idx {
-                            todo.remove(todo_idx);
-                        } else {
-                            if *machine_idx1 == job.machines.len() - 1 {
-                                *machine_idx1 = machine_idx;
-                            }
+                    let _token = tokens.acquire();
 
-                            todo_idx += 1;
-                        }
-                    }
+                    task.start(&job.config, &mut machine)?;
 
-                    job.machines.swap_remove(machine_idx);
+                    machine.tasks[task_idx] = Some(task);
 
-                    job.write(&path)?;
-                }
+                    drop(machine);
 
-                continue;
+                    job.write(path)?;
+
+                    true
+                }
+                None => false,
             }
+        };
+
+        if started {
+            todo.push_back((
+                Instant::now() + Duration::from_secs(job.config.check_interval),
+                task_idx,
+            ));
         }
+    }
+
+    let mut machine = job.machines[machine_idx].lock().unwrap();
 
-        todo.push_back((
-            Instant::now() + Duration::from_secs(job.config.check_interval),
-            machine_idx,
-            task_idx,
-        ));
+    if machine.tasks.iter().all(Option::is_none) {
+        let _token = tokens.acquire();
+
+        machine.delete(&job.config)?;
+        machine.retired = true;
+
+        drop(machine);
+
+        job.write(path)?;
     }
 
     Ok(())
@@ -117,12 +308,49 @@ pub struct Config {
     pub image: String,
     pub size: String,
     pub region: String,
+    #[serde(default)]
+    pub provider: ProviderKind,
+    /// Hosts handed out in order by `ProviderKind::Static`, ignored otherwise.
+    #[serde(default)]
+    pub static_hosts: Vec<String>,
     pub ssh_key: String,
     pub ssh_user: String,
+    /// Defaults to empty for job files written before the native SSH client
+    /// existed; set this (or `shell_fallback`) before resuming such a job.
+    #[serde(default)]
+    pub ssh_identity_file: PathBuf,
     pub install_cmd: String,
     pub check_interval: u64,
     #[serde(default)]
     pub fetch_partial_results: bool,
+    /// Falls back to shelling out to the `ssh`/`scp`/`rsync` binaries instead of
+    /// using the native SSH client, for environments where those are preferred.
+    #[serde(default)]
+    pub shell_fallback: bool,
+    /// Maximum number of retries for a transient provider/SSH failure before
+    /// giving up and propagating the error. Defaults to 0 (no retries) so a
+    /// job file written before retries existed keeps its original
+    /// fail-immediately behavior.
+    #[serde(default)]
+    pub max_retries: u32,
+    /// Base delay in seconds for the exponential backoff between retries.
+    #[serde(default = "default_base_delay")]
+    pub base_delay: u64,
+    /// Maximum number of SSH/provider operations allowed to run at the same
+    /// time across all machines, so a large job cannot overwhelm the local
+    /// machine or the provider's API rate limits. Defaults to 1 (fully
+    /// serial) to match the behavior of job files written before the
+    /// jobserver existed.
+    #[serde(default = "default_max_parallelism")]
+    pub max_parallelism: usize,
+}
+
+fn default_base_delay() -> u64 {
+    1
+}
+
+fn default_max_parallelism() -> usize {
+    1
 }
 
 const SSH_OPTS: &[&str] = &[
@@ -133,4 +361,4 @@ const SSH_OPTS: &[&str] = &[
     "UserKnownHostsFile=/dev/null",
 ];
 
-type Fallible<T = ()> = Result<T, Box<dyn Error>>;
+type Fallible<T = ()> = Result<T, Error>;
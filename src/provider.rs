@@ -0,0 +1,206 @@
+use std::process::{Command, Stdio};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, Config, Fallible};
+
+/// Abstracts over the cloud (or on-prem) backend used to provision machines,
+/// so the scheduling loop in `main` does not need to know about `doctl` or
+/// any other provider-specific command line.
+pub trait Provider {
+    /// Returns the provider-assigned id and reachable IP address.
+    fn create(&self, name: &str, config: &Config) -> Fallible<(String, String)>;
+
+    fn delete(&self, id: &str, config: &Config) -> Fallible;
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProviderKind {
+    #[default]
+    DigitalOcean,
+    Static,
+}
+
+pub fn provider(config: &Config) -> Box<dyn Provider> {
+    match config.provider {
+        ProviderKind::DigitalOcean => Box::new(DigitalOcean),
+        ProviderKind::Static => Box::new(Static),
+    }
+}
+
+pub struct DigitalOcean;
+
+impl Provider for DigitalOcean {
+    fn create(&self, name: &str, config: &Config) -> Fallible<(String, String)> {
+        let doctl = Command::new("doctl")
+            .args(&[
+                "compute",
+                "droplet",
+                "create",
+                "--wait",
+                "--image",
+                &config.image,
+                "--size",
+                &config.size,
+                "--region",
+                &config.region,
+                "--ssh-keys",
+                &config.ssh_key,
+                "--format",
+                "ID,PublicIPv4",
+                "--no-header",
+            ])
+            .arg(name)
+            .stderr(Stdio::piped())
+            .output()?;
+
+        if !doctl.status.success() {
+            let stderr = String::from_utf8_lossy(&doctl.stderr);
+            return Err(classify_doctl_failure("create", name, &stderr));
+        }
+
+        let stdout = String::from_utf8(doctl.stdout)?;
+        let mut fields = stdout.split_whitespace();
+
+        let id = fields.next().ok_or("Missing Droplet ID")?.to_owned();
+        let ip = fields.next().ok_or("Missing Droplet IP")?.to_owned();
+
+        Ok((id, ip))
+    }
+
+    fn delete(&self, id: &str, _config: &Config) -> Fallible {
+        let doctl = Command::new("doctl")
+            .args(&["compute", "droplet", "delete", "--force"])
+            .arg(id)
+            .stderr(Stdio::piped())
+            .output()?;
+
+        if !doctl.status.success() {
+            let stderr = String::from_utf8_lossy(&doctl.stderr);
+            return Err(classify_doctl_failure("delete", id, &stderr));
+        }
+
+        Ok(())
+    }
+}
+
+fn classify_doctl_failure(action: &str, name: &str, stderr: &str) -> Error {
+    let stderr = stderr.trim();
+
+    if stderr.to_lowercase().contains("rate limit") || stderr.contains("429") {
+        Error::Transient(format!(
+            "doctl was rate-limited while trying to {} machine {}: {}",
+            action, name, stderr,
+        ))
+    } else {
+        Error::Provider(format!("Failed to {} machine {}: {}", action, name, stderr,))
+    }
+}
+
+/// Targets a static pool of pre-existing hosts listed in `Config::static_hosts`,
+/// useful for on-prem clusters: `create`/`delete` only hand out/forget an IP,
+/// they never actually provision or tear down anything.
+pub struct Static;
+
+impl Provider for Static {
+    fn create(&self, name: &str, config: &Config) -> Fallible<(String, String)> {
+        let index: usize = name
+            .rsplit('-')
+            .next()
+            .and_then(|suffix| suffix.parse().ok())
+            .ok_or_else(|| format!("Cannot derive static host index from machine name {}", name))?;
+
+        let ip = config
+            .static_hosts
+            .get(index)
+            .ok_or_else(|| format!("Not enough static hosts configured for machine {}", name))?
+            .clone();
+
+        Ok((ip.clone(), ip))
+    }
+
+    fn delete(&self, _id: &str, _config: &Config) -> Fallible {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(static_hosts: Vec<String>) -> Config {
+        Config {
+            max_machines: 1,
+            tasks_per_machine: 1,
+            name: String::new(),
+            image: String::new(),
+            size: String::new(),
+            region: String::new(),
+            provider: Default::default(),
+            static_hosts,
+            ssh_key: String::new(),
+            ssh_user: String::new(),
+            ssh_identity_file: Default::default(),
+            install_cmd: String::new(),
+            check_interval: 0,
+            fetch_partial_results: false,
+            shell_fallback: false,
+            max_retries: 0,
+            base_delay: 0,
+            max_parallelism: 1,
+        }
+    }
+
+    #[test]
+    fn static_create_returns_host_at_trailing_index() {
+        let config = config(vec!["10.0.0.1".into(), "10.0.0.2".into()]);
+
+        let (id, ip) = Static.create("machine-1", &config).unwrap();
+
+        assert_eq!(id, "10.0.0.2");
+        assert_eq!(ip, "10.0.0.2");
+    }
+
+    #[test]
+    fn static_create_fails_without_numeric_suffix() {
+        let config = config(vec!["10.0.0.1".into()]);
+
+        assert!(Static.create("machine", &config).is_err());
+    }
+
+    #[test]
+    fn static_create_fails_with_non_numeric_suffix() {
+        let config = config(vec!["10.0.0.1".into()]);
+
+        assert!(Static.create("machine-abc", &config).is_err());
+    }
+
+    #[test]
+    fn static_create_fails_when_index_out_of_range() {
+        let config = config(vec!["10.0.0.1".into()]);
+
+        assert!(Static.create("machine-1", &config).is_err());
+    }
+
+    #[test]
+    fn classify_doctl_failure_detects_rate_limit_case_insensitively() {
+        let err = classify_doctl_failure("create", "machine-0", "Error: Rate Limit exceeded");
+
+        assert!(matches!(err, Error::Transient(_)));
+    }
+
+    #[test]
+    fn classify_doctl_failure_detects_429_status() {
+        let err = classify_doctl_failure("create", "machine-0", "HTTP 429 Too Many Requests");
+
+        assert!(matches!(err, Error::Transient(_)));
+    }
+
+    #[test]
+    fn classify_doctl_failure_is_provider_error_otherwise() {
+        let err = classify_doctl_failure("create", "machine-0", "droplet limit exceeded");
+
+        assert!(matches!(err, Error::Provider(_)));
+    }
+}
@@ -1,92 +1,397 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{create_dir_all, metadata, read_dir, read_to_string, remove_dir_all, remove_file, write, File};
+use std::io::Read;
+use std::ops::Range;
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::time::{Duration, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
+use ssh2::Sftp;
 
-use crate::{machine::Machine, Config, Fallible, SSH_OPTS};
+use crate::{
+    error::{retry, Error},
+    machine::{classify_ssh_failure, Machine},
+    Config, Fallible, SSH_OPTS,
+};
+
+const DONE_MARKER: &str = ".dospawn-done";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Task {
     pub name: String,
     pub cmd: String,
-    pub repeat: Option<usize>,
+    /// For a templated task with `{{index}}` placeholders in `name`/`cmd`,
+    /// the remaining half-open range of indices still to dispatch.
+    /// `Job::next_task` peels off `range.start` on each call, substitutes it
+    /// for `{{index}}` and re-queues the rest until the range is exhausted.
+    pub range: Option<Range<usize>>,
+    /// Content hash of `binary`/`inputs`/`cmd`/`name`, set by `Job::next_task`
+    /// and used to detect already-completed tasks via `is_cached`.
+    #[serde(default)]
+    pub hash: Option<String>,
 }
 
 impl Task {
-    pub fn start(&self, config: &Config, machine: &Machine) -> Fallible {
+    /// Whether a previous run already completed this task with the same
+    /// inputs, letting the caller skip provisioning and re-running it.
+    pub fn is_cached(&self) -> bool {
+        let hash = match &self.hash {
+            Some(hash) => hash,
+            None => return false,
+        };
+
+        read_to_string(Path::new(&self.name).join(DONE_MARKER))
+            .map(|marker| marker.trim() == hash)
+            .unwrap_or(false)
+    }
+
+    pub fn start(&self, config: &Config, machine: &mut Machine) -> Fallible {
         println!("Starting task {} on machine {}", self.name, machine.name);
 
         let cmd = format!(
-            "rm -rf {name} && mkdir {name} && cd {name} && (nohup {cmd} >stdout 2>stderr &)",
+            "rm -rf {name} && mkdir {name} && cd {name} && \
+             (nohup sh -c '{cmd}; echo $? >.dospawn-exit' >stdout 2>stderr &)",
             name = self.name,
             cmd = self.cmd,
         );
 
-        let ssh = Command::new("ssh")
-            .args(SSH_OPTS)
-            .arg(format!("{}@{}", config.ssh_user, machine.ip))
-            .arg("--")
-            .arg(cmd)
-            .status()?;
-
-        if !ssh.success() {
-            return Err(format!(
-                "Failed to start task {} on machine {}",
-                self.name, machine.name
-            )
-            .into());
-        }
+        retry(config, || {
+            if config.shell_fallback {
+                let ssh = Command::new("ssh")
+                    .args(SSH_OPTS)
+                    .arg(format!("{}@{}", config.ssh_user, machine.ip))
+                    .arg("--")
+                    .arg(&cmd)
+                    .status()?;
 
-        Ok(())
+                if !ssh.success() {
+                    return Err(classify_ssh_failure(
+                        format!(
+                            "Failed to start task {} on machine {}",
+                            self.name, machine.name
+                        ),
+                        ssh,
+                    ));
+                }
+            } else {
+                let machine_name = machine.name.clone();
+
+                machine.with_session(config, |session| {
+                    let mut channel = session.channel_session()?;
+                    channel.exec(&cmd)?;
+                    channel.wait_close()?;
+
+                    if channel.exit_status()? != 0 {
+                        return Err(Error::Provider(format!(
+                            "Failed to start task {} on machine {}",
+                            self.name, machine_name
+                        )));
+                    }
+
+                    Ok(())
+                })?;
+            }
+
+            Ok(())
+        })
     }
 
-    pub fn check(&self, config: &Config, binary: &Path, machine: &Machine) -> Fallible<bool> {
+    pub fn check(&self, config: &Config, machine: &mut Machine) -> Fallible<bool> {
         println!("Checking task {} on machine {}", self.name, machine.name);
 
-        let binary_file_name = binary
-            .file_name()
-            .ok_or("Missing binary file name")?
-            .to_str()
-            .ok_or("Invalid binary file name")?;
+        let cmd = format!("test -e {name}/.dospawn-exit", name = self.name);
 
-        let cmd = format!("pidof {}", binary_file_name);
+        retry(config, || {
+            if config.shell_fallback {
+                let ssh = Command::new("ssh")
+                    .args(SSH_OPTS)
+                    .arg(format!("{}@{}", config.ssh_user, machine.ip))
+                    .arg("--")
+                    .arg(&cmd)
+                    .stdout(Stdio::null())
+                    .status()?;
 
-        let ssh = Command::new("ssh")
-            .args(SSH_OPTS)
-            .arg(format!("{}@{}", config.ssh_user, machine.ip))
-            .arg("--")
-            .arg(cmd)
-            .stdout(Stdio::null())
-            .status()?;
+                Ok(ssh.success())
+            } else {
+                machine.with_session(config, |session| {
+                    let mut channel = session.channel_session()?;
+                    channel.exec(&cmd)?;
+                    channel.wait_close()?;
 
-        Ok(!ssh.success())
+                    Ok(channel.exit_status()? == 0)
+                })
+            }
+        })
     }
 
-    pub fn fetch_results(&self, config: &Config, machine: &Machine) -> Fallible {
+    pub fn fetch_results(
+        &self,
+        config: &Config,
+        machine: &mut Machine,
+        finished: bool,
+    ) -> Fallible {
         println!(
             "Fetching results of task {} from machine {}",
             self.name, machine.name
         );
 
-        let rsync = Command::new("rsync")
-            .arg("-e")
-            .arg(format!("ssh {}", SSH_OPTS.join(" ")))
-            .arg("--recursive")
-            .arg("--delete")
-            .arg("--inplace")
-            .arg("--compress")
-            .arg(format!("{}@{}:{}/", config.ssh_user, machine.ip, self.name))
-            .arg(&self.name)
-            .status()?;
-
-        if !rsync.success() {
-            return Err(format!(
-                "Failed to fetch results of task {} from machine {}",
-                self.name, machine.name,
-            )
-            .into());
+        retry(config, || {
+            if config.shell_fallback {
+                let rsync = Command::new("rsync")
+                    .arg("-e")
+                    .arg(format!("ssh {}", SSH_OPTS.join(" ")))
+                    .arg("--recursive")
+                    .arg("--delete")
+                    .arg("--inplace")
+                    .arg("--compress")
+                    .arg(format!("{}@{}:{}/", config.ssh_user, machine.ip, self.name))
+                    .arg(&self.name)
+                    .status()?;
+
+                if !rsync.success() {
+                    return Err(classify_ssh_failure(
+                        format!(
+                            "Failed to fetch results of task {} from machine {}",
+                            self.name, machine.name,
+                        ),
+                        rsync,
+                    ));
+                }
+            } else {
+                create_dir_all(&self.name)?;
+
+                machine.with_session(config, |session| {
+                    let sftp = session.sftp()?;
+
+                    download_dir(&sftp, Path::new(&self.name), Path::new(&self.name))
+                })?;
+            }
+
+            Ok(())
+        })?;
+
+        if finished {
+            if let Some(hash) = &self.hash {
+                write(Path::new(&self.name).join(DONE_MARKER), hash)?;
+            }
         }
 
         Ok(())
     }
 }
+
+fn download_dir(sftp: &Sftp, remote: &Path, local: &Path) -> Fallible {
+    let mut visited = HashSet::new();
+
+    for (path, stat) in sftp.readdir(remote)? {
+        let file_name = path.file_name().ok_or("Missing file name")?;
+        visited.insert(file_name.to_owned());
+
+        let local_path = local.join(file_name);
+
+        if stat.is_dir() {
+            create_dir_all(&local_path)?;
+            download_dir(sftp, &path, &local_path)?;
+        } else if !is_up_to_date(&local_path, &stat) {
+            let mut remote_file = sftp.open(&path)?;
+            let mut data = Vec::new();
+            remote_file
+                .read_to_end(&mut data)
+                .map_err(|err| Error::Transient(err.to_string()))?;
+
+            write(&local_path, data)?;
+
+            if let Some(mtime) = stat.mtime {
+                let _ = File::open(&local_path)?.set_modified(UNIX_EPOCH + Duration::from_secs(mtime));
+            }
+        }
+    }
+
+    remove_stale_entries(local, &visited)
+}
+
+/// Removes local entries under `local` that were not seen in the matching
+/// remote directory listing, mirroring the `--delete` behaviour of the
+/// `shell_fallback` rsync path so both paths converge on the same result
+/// directory instead of the native one accumulating stale files forever.
+fn remove_stale_entries(local: &Path, visited: &HashSet<OsString>) -> Fallible {
+    let entries = match read_dir(local) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+
+        if visited.contains(&entry.file_name()) {
+            continue;
+        }
+
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            remove_dir_all(&path)?;
+        } else {
+            remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `local_path` already holds the same size/mtime as the remote
+/// `stat`, so `download_dir` can skip re-transferring it on every poll
+/// instead of pulling the whole result set from scratch each time.
+fn is_up_to_date(local_path: &Path, stat: &ssh2::FileStat) -> bool {
+    let metadata = match metadata(local_path) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+
+    let size_matches = stat.size.map_or(true, |size| metadata.len() == size);
+
+    let mtime_matches = match (stat.mtime, metadata.modified()) {
+        // The remote mtime only has whole-second resolution.
+        (Some(mtime), Ok(modified)) => modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(false, |since| since.as_secs() == mtime),
+        _ => false,
+    };
+
+    size_matches && mtime_matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "dospawn-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            name.len(),
+        ));
+
+        let _ = remove_dir_all(&path);
+        create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn is_cached_false_without_hash() {
+        let task = Task {
+            name: temp_dir("no-hash").to_str().unwrap().to_owned(),
+            cmd: String::new(),
+            range: None,
+            hash: None,
+        };
+
+        assert!(!task.is_cached());
+    }
+
+    #[test]
+    fn is_cached_false_without_marker() {
+        let dir = temp_dir("no-marker");
+
+        let task = Task {
+            name: dir.to_str().unwrap().to_owned(),
+            cmd: String::new(),
+            range: None,
+            hash: Some("deadbeef".into()),
+        };
+
+        assert!(!task.is_cached());
+    }
+
+    #[test]
+    fn is_cached_false_on_hash_mismatch() {
+        let dir = temp_dir("mismatch");
+        write(dir.join(DONE_MARKER), "otherhash").unwrap();
+
+        let task = Task {
+            name: dir.to_str().unwrap().to_owned(),
+            cmd: String::new(),
+            range: None,
+            hash: Some("deadbeef".into()),
+        };
+
+        assert!(!task.is_cached());
+    }
+
+    #[test]
+    fn is_cached_true_on_hash_match() {
+        let dir = temp_dir("match");
+        write(dir.join(DONE_MARKER), "deadbeef").unwrap();
+
+        let task = Task {
+            name: dir.to_str().unwrap().to_owned(),
+            cmd: String::new(),
+            range: None,
+            hash: Some("deadbeef".into()),
+        };
+
+        assert!(task.is_cached());
+    }
+
+    #[test]
+    fn up_to_date_requires_matching_size_and_mtime() {
+        let dir = temp_dir("up-to-date");
+        let path = dir.join("result.txt");
+        write(&path, b"hello").unwrap();
+
+        let mtime = metadata(&path).unwrap().modified().unwrap();
+        let mtime_secs = mtime.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        let file_stat = |size, mtime| ssh2::FileStat {
+            size: Some(size),
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: Some(mtime),
+        };
+
+        assert!(is_up_to_date(&path, &file_stat(5, mtime_secs)));
+        assert!(!is_up_to_date(&path, &file_stat(6, mtime_secs)));
+        assert!(!is_up_to_date(&path, &file_stat(5, mtime_secs + 1)));
+    }
+
+    #[test]
+    fn up_to_date_false_for_missing_local_file() {
+        let dir = temp_dir("missing");
+
+        let stat = ssh2::FileStat {
+            size: Some(1),
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: Some(0),
+        };
+
+        assert!(!is_up_to_date(&dir.join("missing.txt"), &stat));
+    }
+
+    #[test]
+    fn remove_stale_entries_deletes_unvisited_files_and_dirs() {
+        let dir = temp_dir("stale");
+        write(dir.join("kept.txt"), b"kept").unwrap();
+        write(dir.join("stale.txt"), b"stale").unwrap();
+        create_dir_all(dir.join("stale_dir")).unwrap();
+        write(dir.join("stale_dir").join("inner.txt"), b"inner").unwrap();
+
+        let mut visited = std::collections::HashSet::new();
+        visited.insert(std::ffi::OsString::from("kept.txt"));
+
+        remove_stale_entries(&dir, &visited).unwrap();
+
+        assert!(dir.join("kept.txt").exists());
+        assert!(!dir.join("stale.txt").exists());
+        assert!(!dir.join("stale_dir").exists());
+    }
+}